@@ -1,15 +1,37 @@
+use clap::Parser;
+use mio::net::{TcpListener as MioTcpListener, TcpStream as MioTcpStream};
+#[cfg(unix)]
+use mio::net::{UnixListener as MioUnixListener, UnixStream as MioUnixStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
 use serde::{Deserialize, Serialize};
 use serde_json::to_string;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs::OpenOptions;
 use std::io::{self, BufRead, Read, Write};
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex, Condvar};
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{mpsc, Arc, Mutex, Condvar};
 use std::thread;
+use std::time::{Duration, Instant};
 use chrono::Local;
 
-// vector clock represented by a vector
-type VectorClock = [i32; 3];
+// token assigned to the listener's accept-readiness registration
+const LISTEN: Token = Token(0);
+// token assigned to the waker that unblocks the poll loop for shutdown
+const WAKE: Token = Token(1);
+// connection tokens are handed out starting here so they never collide with LISTEN/WAKE
+const FIRST_CONNECTION_TOKEN: usize = 2;
+// longest write_all will keep waiting out WouldBlock before giving up on a write,
+// so a stalled peer falls back into send_with_retry's bounded backoff/retry
+// instead of blocking the sending thread forever
+const WRITE_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// vector clock keyed by node id, so nodes can join the cluster without a recompile
+type VectorClock = BTreeMap<String, u64>;
 
 // server message structure
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -18,85 +40,320 @@ struct ServerMessage {
     clock: VectorClock,
 }
 
-// tcp server structure
+// configurable retry/rate parameters for outbound sends, so a flaky peer or a
+// transient connect failure doesn't crash the whole node
+#[derive(Clone)]
+struct SendConfig {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    rate_limit: Duration,
+    throughput_report_interval: Duration,
+}
+
+impl Default for SendConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            rate_limit: Duration::from_millis(0),
+            throughput_report_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+// the transport a node listens on: a plain tcp socket, or (unix only) a unix
+// domain socket for same-host clusters. holds mio types so the poll loop can
+// register interest directly on whichever variant is active
+enum Listener {
+    Tcp(MioTcpListener),
+    #[cfg(unix)]
+    Unix(MioUnixListener),
+}
+
+// an accepted connection, matching the variant of the listener it came from
+enum Connection {
+    Tcp(MioTcpStream),
+    #[cfg(unix)]
+    Unix(MioUnixStream),
+}
+
+impl Listener {
+    // binds a listener for a uri-style address ("tcp://host:port" or "unix:/path"),
+    // adopting a systemd-activated socket instead of binding one if LISTEN_FDS/LISTEN_PID
+    // name this process
+    fn bind(address: &str) -> Self {
+        if let Some(listener) = Listener::from_systemd(address) {
+            return listener;
+        }
+        if let Some(path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path).unwrap();
+                listener.set_nonblocking(true).expect("Failed to set non-blocking");
+                return Listener::Unix(MioUnixListener::from_std(listener));
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = path;
+                panic!("unix domain sockets are only supported on unix platforms");
+            }
+        }
+        let tcp_address = address.strip_prefix("tcp://").unwrap_or(address);
+        let listener = TcpListener::bind(tcp_address).unwrap();
+        listener.set_nonblocking(true).expect("Failed to set non-blocking");
+        Listener::Tcp(MioTcpListener::from_std(listener))
+    }
+
+    // adopts fd 3 when this process was socket-activated (e.g. by systemd) for the
+    // requested address, rather than binding a fresh socket
+    #[cfg(unix)]
+    fn from_systemd(address: &str) -> Option<Self> {
+        let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+        if pid != std::process::id() {
+            return None;
+        }
+        let fd_count: u32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+        if fd_count == 0 {
+            return None;
+        }
+        // systemd numbers passed descriptors starting at 3; we only use a single socket
+        const SD_LISTEN_FDS_START: i32 = 3;
+        if address.starts_with("unix:") {
+            let listener = unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+            listener.set_nonblocking(true).expect("Failed to set non-blocking");
+            Some(Listener::Unix(MioUnixListener::from_std(listener)))
+        } else {
+            let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+            listener.set_nonblocking(true).expect("Failed to set non-blocking");
+            Some(Listener::Tcp(MioTcpListener::from_std(listener)))
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from_systemd(_address: &str) -> Option<Self> {
+        None
+    }
+
+    // accepts a single pending connection, preserving non-blocking WouldBlock errors
+    fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+}
+
+impl mio::event::Source for Listener {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.register(registry, token, interests),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.deregister(registry),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl mio::event::Source for Connection {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.register(registry, token, interests),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.deregister(registry),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+// dials a uri-style address ("tcp://host:port" or "unix:/path") and returns the
+// resulting connection
+fn dial(address: &str) -> io::Result<Connection> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            let stream = UnixStream::connect(path)?;
+            stream.set_nonblocking(true)?;
+            return Ok(Connection::Unix(MioUnixStream::from_std(stream)));
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            panic!("unix domain sockets are only supported on unix platforms");
+        }
+    }
+    let tcp_address = address.strip_prefix("tcp://").unwrap_or(address);
+    let stream = TcpStream::connect(tcp_address)?;
+    stream.set_nonblocking(true)?;
+    Ok(Connection::Tcp(MioTcpStream::from_std(stream)))
+}
+
+// writes `payload` to `connection` in full, looping on WouldBlock instead of
+// treating a full socket send buffer as a connection failure. dial() marks every
+// connection non-blocking, so a plain write_all can return WouldBlock partway
+// through under backpressure; that used to be counted as a send failure and
+// trigger a full dial+backoff reconnect instead of just waiting for the buffer to
+// drain
+fn write_all(connection: &mut Connection, payload: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    // reset on every successful write, so this bounds time since the last byte
+    // went out (a stall), not total transfer time (which a slow-but-progressing
+    // peer could legitimately exceed for a large payload)
+    let mut last_progress_at = Instant::now();
+    while written < payload.len() {
+        let result = match connection {
+            Connection::Tcp(stream) => stream.write(&payload[written..]),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(&payload[written..]),
+        };
+        match result {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole payload")),
+            Ok(n) => {
+                written += n;
+                last_progress_at = Instant::now();
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if last_progress_at.elapsed() >= WRITE_STALL_TIMEOUT {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "write stalled on WouldBlock"));
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// pulls one length-prefixed frame out of `buffer` and returns its body, leaving any
+// trailing bytes (a partial next frame) in place. mirrors the read_exact(prefix) then
+// read_exact(len) framing, adapted to the bytes already buffered from a non-blocking read
+fn take_frame(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buffer.len() < 4 {
+        return None;
+    }
+    let len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    if buffer.len() < 4 + len {
+        return None;
+    }
+    let frame = buffer[4..4 + len].to_vec();
+    buffer.drain(..4 + len);
+    Some(frame)
+}
+
+// builds the length-prefixed wire payload for a message, shared by send_event and
+// broadcast so the framing logic only lives in one place
+fn frame_message(msg: &ServerMessage) -> Vec<u8> {
+    let msg_json = to_string(msg).unwrap();
+    let body = msg_json.as_bytes();
+    let mut payload = Vec::with_capacity(4 + body.len());
+    payload.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    payload.extend_from_slice(body);
+    payload
+}
+
+// sets the shutdown flag, wakes anyone blocked on the condvar, and nudges the poll
+// loop's waker (if it has been created yet) so it unblocks immediately instead of
+// waiting out its poll timeout. takes the Arcs directly rather than a &Server so it
+// can be called from the ctrlc handler without going through the server's mutex
+fn trigger_shutdown(shutdown_signal: &Arc<(Mutex<bool>, Condvar)>, waker: &Arc<Mutex<Option<Waker>>>) {
+    let (lock, cvar) = &**shutdown_signal;
+    *lock.lock().unwrap() = true;
+    cvar.notify_all();
+    if let Some(waker) = waker.lock().unwrap().as_ref() {
+        let _ = waker.wake();
+    }
+}
+
+// tcp server structure. holds only the state both the stdin loop and the event
+// reactor need to share (clock, peers, send/throughput bookkeeping) — the listener
+// and its connections live purely inside the event reactor's own thread so that
+// servicing events never requires holding this struct's mutex for longer than it
+// takes to read or update the clock. shutdown is coordinated through its own Arcs,
+// created and held directly by main, so triggering it never needs this mutex either
 struct Server {
     id: String,
-    clock_index: usize,
     clock: VectorClock,
-    listener: TcpListener,
-    shutdown_signal: Arc<(Mutex<bool>, Condvar)>,
+    send_config: SendConfig,
+    bytes_sent: u64,
+    throughput_window_started_at: Instant,
+    // known peers this node can reach, keyed by id, so `broadcast` has somewhere to fan out to
+    peers: BTreeMap<String, String>,
 }
 
 // server implementation
 impl Server {
-    // binds server to port
-    fn new(id: &str, clock_index: usize, port: u16) -> Self {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
-        listener.set_nonblocking(true).expect("Failed to set non-blocking");
-        let mut clock = [0; 3];
-        clock[clock_index] = 1;
+    // id is this node's key in every vector clock
+    fn new(id: &str, send_config: SendConfig) -> Self {
+        let mut clock = VectorClock::new();
+        clock.insert(id.to_string(), 1);
         Self {
             id: id.to_string(),
-            clock_index,
             clock,
-            listener,
-            shutdown_signal: Arc::new((Mutex::new(false), Condvar::new())),
+            send_config,
+            peers: BTreeMap::new(),
+            bytes_sent: 0,
+            throughput_window_started_at: Instant::now(),
         }
     }
 
-    // increments logical time
+    // increments logical time for this node
     fn increment(&mut self) {
-        self.clock[self.clock_index] += 1;
+        *self.clock.entry(self.id.clone()).or_insert(0) += 1;
     }
 
-    // updates this clock based on larger time value
+    // updates this clock based on the union of keys in another clock, taking the
+    // element-wise max, then increments the local entry
     fn update_clock(&mut self, other_clock: &VectorClock) {
-        for i in 0..3 {
-            self.clock[i] = self.clock[i].max(other_clock[i]);
+        for node_id in other_clock.keys() {
+            let ours = self.clock.entry(node_id.clone()).or_insert(0);
+            *ours = (*ours).max(other_clock[node_id]);
         }
         self.increment();
     }
 
-    // sends event to other server at other_address
-    fn send_event(&mut self, other_address: &str) {
-        self.increment();
-        let msg = ServerMessage {
-            sender_id: self.id.clone(),
-            clock: self.clock,
-        };
-        let mut stream = TcpStream::connect(other_address).unwrap();
-        let msg_json = to_string(&msg).unwrap();
-        stream.write_all(msg_json.as_bytes()).unwrap();
-        let log_msg = format!("{} send an event to {} with clock {:?}", self.id, other_address, self.clock);
-        println!("{}", log_msg);
-        Server::log_event(&log_msg);
-    }
-
-    // handles incoming events
-    fn handle_events(&mut self) {
-        while !*self.shutdown_signal.0.lock().unwrap() {
-            match self.listener.accept() {
-                Ok((mut stream, _)) => {
-                    let mut buffer = [0; 1024];
-                    let _ = stream.read(&mut buffer).unwrap();
-                    let msg: ServerMessage = serde_json::from_slice(&buffer).expect("cannot deserialize message");
-                    self.update_clock(&msg.clock);
-                    let log_msg = format!("received event from {}, clock is now {:?}", msg.sender_id, self.clock);
-                    println!("{}", log_msg);
-                    Server::log_event(&log_msg);
-                },
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // No incoming connection, break the loop to avoid busy waiting
-                    break;
-                },
-                Err(e) => {
-                    eprintln!("Error accepting connection: {}", e);
-                    continue;
-                }
-            }
-        }
-    }
-
     // event logger
     fn log_event(event: &str) {
         let now = Local::now();
@@ -112,70 +369,409 @@ impl Server {
     }
 }
 
-// driver code
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("need to format command like this: {} <server_id> <port>", args[0]);
-        return;
+// sends event to other server at other_address, retrying transient failures instead
+// of panicking the node. only locks `server` long enough to bump the clock and
+// snapshot id/clock/send_config; the dial, write, retries and rate-limit sleep all
+// run afterwards with no lock held, so they never stall the poll reactor
+fn send_event(server: &Arc<Mutex<Server>>, other_address: &str) {
+    let (id, clock, send_config) = {
+        let mut server = server.lock().unwrap();
+        server.increment();
+        (server.id.clone(), server.clock.clone(), server.send_config.clone())
+    };
+    let payload = frame_message(&ServerMessage { sender_id: id.clone(), clock: clock.clone() });
+
+    match send_with_retry(server, &id, &send_config, other_address, &payload) {
+        Ok(()) => {
+            let log_msg = format!("{} send an event to {} with clock {:?}", id, other_address, clock);
+            println!("{}", log_msg);
+            Server::log_event(&log_msg);
+        }
+        Err(e) => {
+            let log_msg = format!("{} gave up sending an event to {}: {}", id, other_address, e);
+            eprintln!("{}", log_msg);
+            Server::log_event(&log_msg);
+        }
+    }
+
+    thread::sleep(send_config.rate_limit);
+}
+
+// dials and writes `payload`, retrying with exponential backoff (bounded by
+// send_config.max_backoff) on connect/write failure before giving up. reads the
+// retry parameters from a snapshot of send_config rather than from the locked
+// server, so the backoff sleeps never hold the `Server` mutex; the server is only
+// locked briefly by report_throughput after a successful send
+fn send_with_retry(server: &Arc<Mutex<Server>>, id: &str, send_config: &SendConfig, address: &str, payload: &[u8]) -> io::Result<()> {
+    let mut backoff = send_config.initial_backoff;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let result = dial(address).and_then(|mut connection| write_all(&mut connection, payload));
+        match result {
+            Ok(()) => {
+                report_throughput(server, payload.len());
+                return Ok(());
+            }
+            Err(e) if attempt < send_config.max_retries => {
+                eprintln!(
+                    "{} failed to send to {} (attempt {}/{}): {}, retrying in {:?}",
+                    id, address, attempt, send_config.max_retries, e, backoff
+                );
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(send_config.max_backoff);
+            }
+            Err(e) => return Err(e),
+        }
     }
+}
+
+// tracks bytes sent since the start of the current window, printing a
+// bytes-sent/elapsed throughput line once send_config.throughput_report_interval
+// has elapsed. locks the server only for the duration of this bookkeeping update
+fn report_throughput(server: &Arc<Mutex<Server>>, bytes: usize) {
+    let mut server = server.lock().unwrap();
+    server.bytes_sent += bytes as u64;
+    let elapsed = server.throughput_window_started_at.elapsed();
+    if elapsed >= server.send_config.throughput_report_interval {
+        let rate = server.bytes_sent as f64 / elapsed.as_secs_f64();
+        println!(
+            "{} throughput: {} bytes sent in {:.1}s ({:.1} bytes/s)",
+            server.id,
+            server.bytes_sent,
+            elapsed.as_secs_f64(),
+            rate
+        );
+        server.bytes_sent = 0;
+        server.throughput_window_started_at = Instant::now();
+    }
+}
+
+// increments the local clock once and fans the resulting message out to every
+// configured peer, logging per-peer failures without aborting the rest. the peer
+// list, clock and send_config are snapshotted under a brief lock up front so the
+// sends themselves (and their retries/sleeps via send_with_retry) run lock-free
+fn broadcast(server: &Arc<Mutex<Server>>) {
+    let (id, clock, send_config, peers) = {
+        let mut server = server.lock().unwrap();
+        server.increment();
+        let peers: Vec<(String, String)> = server.peers.iter().map(|(id, address)| (id.clone(), address.clone())).collect();
+        (server.id.clone(), server.clock.clone(), server.send_config.clone(), peers)
+    };
+    let payload = frame_message(&ServerMessage { sender_id: id.clone(), clock: clock.clone() });
+
+    for (peer_id, address) in peers {
+        match send_with_retry(server, &id, &send_config, &address, &payload) {
+            Ok(()) => {
+                let log_msg = format!("{} broadcast an event to {} with clock {:?}", id, peer_id, clock);
+                println!("{}", log_msg);
+                Server::log_event(&log_msg);
+            }
+            Err(e) => {
+                let log_msg = format!("{} failed to broadcast to {}: {}", id, peer_id, e);
+                eprintln!("{}", log_msg);
+                Server::log_event(&log_msg);
+            }
+        }
+    }
+}
 
-    let server_id = &args[1];
-    let port: u16 = args[2].parse().expect("invalid port number");
+// runs the poll reactor for `listener`'s accepted connections until shutdown_signal
+// is set. the listener and its connections are owned locally by this loop rather
+// than living on `Server`, so the only time this function needs the server's mutex
+// is to fold a received message's clock into the shared clock — never for the
+// loop's own lifetime, which is what let the stdin loop's `server.lock()` deadlock
+fn run_event_loop(
+    server: Arc<Mutex<Server>>,
+    mut listener: Listener,
+    shutdown_signal: Arc<(Mutex<bool>, Condvar)>,
+    waker_slot: Arc<Mutex<Option<Waker>>>,
+) {
+    let mut poll = Poll::new().expect("failed to create poll");
+    poll.registry()
+        .register(&mut listener, LISTEN, Interest::READABLE)
+        .expect("failed to register listener");
+    let waker = Waker::new(poll.registry(), WAKE).expect("failed to create waker");
+    *waker_slot.lock().unwrap() = Some(waker);
+
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut buffers: HashMap<Token, Vec<u8>> = HashMap::new();
+    let mut next_token = FIRST_CONNECTION_TOKEN;
+    let mut events = Events::with_capacity(128);
+
+    'poll_loop: loop {
+        if *shutdown_signal.0.lock().unwrap() {
+            break;
+        }
 
-    let clock_index = match server_id.as_str() {
-        "server1" => 0,
-        "server2" => 1,
-        "server3" => 2,
-        _ => {
-            eprintln!("{} is an invalid server id", server_id);
-            return;
+        if let Err(e) = poll.poll(&mut events, Some(Duration::from_millis(200))) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            eprintln!("Error polling for events: {}", e);
+            break;
         }
+
+        for event in events.iter() {
+            match event.token() {
+                WAKE => {
+                    if *shutdown_signal.0.lock().unwrap() {
+                        break 'poll_loop;
+                    }
+                }
+                LISTEN => loop {
+                    match listener.accept() {
+                        Ok(mut connection) => {
+                            let token = Token(next_token);
+                            next_token += 1;
+                            if let Err(e) = poll.registry().register(&mut connection, token, Interest::READABLE) {
+                                eprintln!("Error registering connection: {}", e);
+                                continue;
+                            }
+                            connections.insert(token, connection);
+                            buffers.insert(token, Vec::new());
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            eprintln!("Error accepting connection: {}", e);
+                            break;
+                        }
+                    }
+                },
+                token => {
+                    if read_connection(&server, &mut connections, &mut buffers, token) {
+                        if let Some(mut connection) = connections.remove(&token) {
+                            let _ = poll.registry().deregister(&mut connection);
+                        }
+                        buffers.remove(&token);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// reads everything currently available on `token`'s connection, appending to its
+// buffer, then extracts as many complete JSON messages as have accumulated so far.
+// returns true once the connection has been closed by the peer and should be
+// dropped. the server is locked only once per complete message, to fold its clock
+// into ours, not for the span of the read
+fn read_connection(
+    server: &Arc<Mutex<Server>>,
+    connections: &mut HashMap<Token, Connection>,
+    buffers: &mut HashMap<Token, Vec<u8>>,
+    token: Token,
+) -> bool {
+    let connection = match connections.get_mut(&token) {
+        Some(connection) => connection,
+        None => return true,
     };
+    let buffer = buffers.entry(token).or_default();
+
+    let mut closed = false;
+    loop {
+        let mut chunk = [0u8; 1024];
+        match connection.read(&mut chunk) {
+            Ok(0) => {
+                closed = true;
+                break;
+            }
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                eprintln!("Error reading connection: {}", e);
+                closed = true;
+                break;
+            }
+        }
+    }
+
+    while let Some(frame) = take_frame(buffer) {
+        match serde_json::from_slice::<ServerMessage>(&frame) {
+            Ok(msg) => {
+                let clock = {
+                    let mut server = server.lock().unwrap();
+                    server.update_clock(&msg.clock);
+                    server.clock.clone()
+                };
+                let log_msg = format!("received event from {}, clock is now {:?}", msg.sender_id, clock);
+                println!("{}", log_msg);
+                Server::log_event(&log_msg);
+            }
+            Err(e) => eprintln!("cannot deserialize message: {}", e),
+        }
+    }
+
+    closed
+}
+
+// command-line configuration for a single node, so topologies are described on the
+// command line (or a wrapping script) instead of hardcoded id/port tables
+#[derive(Parser, Debug)]
+#[command(about = "A vector-clock cluster node")]
+struct Config {
+    /// this node's id, used as its key in every vector clock
+    #[arg(long)]
+    id: String,
+
+    /// uri-style address to listen on, e.g. tcp://127.0.0.1:8001 or unix:/tmp/node.sock
+    #[arg(long)]
+    listen: String,
+
+    /// a known peer as "id=address", repeatable for each peer in the cluster
+    #[arg(long = "peer", value_parser = parse_peer)]
+    peer: Vec<(String, String)>,
+
+    /// how many times send_with_retry attempts a send before giving up
+    #[arg(long, default_value_t = SendConfig::default().max_retries)]
+    max_retries: u32,
+
+    /// backoff before the first retry, in milliseconds, doubling on each further attempt
+    #[arg(long, default_value_t = SendConfig::default().initial_backoff.as_millis() as u64)]
+    initial_backoff_ms: u64,
+
+    /// upper bound the doubling backoff is capped at, in milliseconds
+    #[arg(long, default_value_t = SendConfig::default().max_backoff.as_millis() as u64)]
+    max_backoff_ms: u64,
+
+    /// minimum delay enforced after every send_event, in milliseconds
+    #[arg(long, default_value_t = SendConfig::default().rate_limit.as_millis() as u64)]
+    rate_limit_ms: u64,
+
+    /// how often report_throughput prints a bytes-sent/elapsed line, in seconds
+    #[arg(long, default_value_t = SendConfig::default().throughput_report_interval.as_secs())]
+    throughput_report_interval_secs: u64,
+}
+
+impl Config {
+    // builds the SendConfig this node's Server should use from the parsed flags,
+    // so "Expose the retry/rate parameters as config" isn't just SendConfig::default()
+    fn send_config(&self) -> SendConfig {
+        SendConfig {
+            max_retries: self.max_retries,
+            initial_backoff: Duration::from_millis(self.initial_backoff_ms),
+            max_backoff: Duration::from_millis(self.max_backoff_ms),
+            rate_limit: Duration::from_millis(self.rate_limit_ms),
+            throughput_report_interval: Duration::from_secs(self.throughput_report_interval_secs),
+        }
+    }
+}
+
+// parses a single `--peer id=address` argument
+fn parse_peer(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((id, address)) => Ok((id.to_string(), address.to_string())),
+        None => Err(format!("expected id=address, got `{}`", s)),
+    }
+}
+
+// driver code
+fn main() {
+    let config = Config::parse();
+    let server_id = config.id.clone();
+
+    let listener = Listener::bind(&config.listen);
+    let server = Arc::new(Mutex::new(Server::new(&config.id, config.send_config())));
+
+    // known peers become this node's broadcast targets
+    {
+        let mut server = server.lock().unwrap();
+        for (id, address) in config.peer {
+            server.peers.insert(id, address);
+        }
+    }
 
-    let server = Arc::new(Mutex::new(Server::new(server_id, clock_index, port)));
+    // shutdown is coordinated through its own condvar and waker slot, independent
+    // of the Server mutex, so "end" and Ctrl-C can always trigger it even if the
+    // server happens to be locked for something else
+    let shutdown_signal = Arc::new((Mutex::new(false), Condvar::new()));
+    let waker: Arc<Mutex<Option<Waker>>> = Arc::new(Mutex::new(None));
 
-    println!("{} listening on port {}", server_id, port);
+    {
+        let shutdown_signal = Arc::clone(&shutdown_signal);
+        let waker = Arc::clone(&waker);
+        ctrlc::set_handler(move || trigger_shutdown(&shutdown_signal, &waker))
+            .expect("Error setting Ctrl-C handler");
+    }
+
+    println!("{} listening on {}", server_id, config.listen);
 
-    // Spawn a thread to handle incoming events
+    // Spawn a thread to handle incoming events. the listener lives only on this
+    // thread's stack, and the server mutex is locked per-message inside the loop
+    // rather than for the loop's whole lifetime, so the stdin loop below can still
+    // acquire it for "event"/"clock"/"broadcast"/peer-send commands
     let server_clone = Arc::clone(&server);
-    thread::spawn(move || {
-        let mut server = server_clone.lock().unwrap();
+    let shutdown_signal_for_events = Arc::clone(&shutdown_signal);
+    let waker_for_events = Arc::clone(&waker);
+    let event_thread = thread::spawn(move || {
         println!("Starting to handle events...");
-        server.handle_events();
+        run_event_loop(server_clone, listener, shutdown_signal_for_events, waker_for_events);
         println!("Stopped handling events.");
     });
 
-    let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        let input = line.unwrap().trim().to_string();
-        let mut server = server.lock().unwrap();
+    // reads stdin lines on its own thread and forwards them over a channel. a
+    // blocking `stdin.lock().lines()` call can't be interrupted by a signal or by
+    // the event thread stopping, so the main loop below waits on this channel with
+    // a timeout instead, polling shutdown_signal in between lines
+    let (stdin_tx, stdin_rx) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            if stdin_tx.send(line.unwrap().trim().to_string()).is_err() {
+                break;
+            }
+        }
+        // stdin_tx is dropped here on EOF, disconnecting stdin_rx below
+    });
+
+    loop {
+        if *shutdown_signal.0.lock().unwrap() {
+            break;
+        }
+
+        let input = match stdin_rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(input) => input,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // stdin closed (EOF, or piped input ran out): shut down instead of
+                // waiting forever for an "end" that will never arrive
+                println!("stdin closed, shutting down {}", server_id);
+                trigger_shutdown(&shutdown_signal, &waker);
+                break;
+            }
+        };
+
+        // "end" never needs the Server lock at all, so it can't be blocked by
+        // whatever the event thread (or a slow send/broadcast) happens to be doing
         if input == "end" {
             println!("Shutting down {}", server_id);
+            trigger_shutdown(&shutdown_signal, &waker);
             break;
         } else if input == "event" {
+            let mut server = server.lock().unwrap();
             server.increment();
             println!("{} clock is now {:?}", server_id, server.clock);
             Server::log_event(&format!("{} had a local event and updated clock to {:?}", server_id, server.clock));
         } else if input == "clock" {
+            let server = server.lock().unwrap();
             println!("{} clock: {:?}", server_id, server.clock);
+        } else if input == "broadcast" {
+            broadcast(&server);
         } else {
             let target_server_id = input;
-            let target_port = match target_server_id.as_str() {
-                "server1" => 8001,
-                "server2" => 8002,
-                "server3" => 8003,
-                _ => {
-                    println!("{} is an invalid server id", target_server_id);
-                    continue;
-                }
-            };
-            let to_address = format!("127.0.0.1:{}", target_port);
-            server.send_event(&to_address);
+            let address = server.lock().unwrap().peers.get(&target_server_id).cloned();
+            match address {
+                Some(address) => send_event(&server, &address),
+                None => println!("{} is an invalid server id", target_server_id),
+            }
         }
     }
 
-    // Wait for the event handling thread to finish
-    thread::sleep(std::time::Duration::from_secs(1));
+    // Wait for the event handling thread to shut down cleanly, closing its sockets,
+    // instead of guessing with a fixed sleep (log_event writes each log line out as
+    // it happens, so there is no buffered log left to flush). shutdown_signal
+    // is always set by this point (via "end", EOF, or the Ctrl-C handler), so the
+    // reactor is already on its way out and this join actually returns
+    event_thread.join().unwrap();
     println!("Main thread exiting.");
 }